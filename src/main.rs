@@ -1,15 +1,249 @@
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{fence, AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
 
-// uncomment for incorrect (relaxed)
-const ACK: Ordering = Ordering::Relaxed;
-const REL: Ordering = Ordering::Relaxed;
-// uncomment for correct (acquire/release)
-//const ACK: Ordering = Ordering::Acquire;
-//const REL: Ordering = Ordering::Release;
+// force every SpinLock acquire/release to Relaxed, ignoring the CLI orderings
+const FORCE_RELAXED: bool = false;
 
-static FLAG: AtomicBool = AtomicBool::new(false);
-static mut SHARED_VALUE: u32 = 0;
+// doublings of spin_loop() before giving up and yielding
+const MAX_BACKOFF_STEPS: u32 = 6;
+
+struct SpinLock {
+    locked: AtomicBool,
+}
+
+impl SpinLock {
+    const fn new() -> Self {
+        SpinLock {
+            locked: AtomicBool::new(false),
+        }
+    }
+
+    // spins with exponential backoff; guard releases with `rel` on drop
+    fn lock(&self, ack: Ordering, rel: Ordering) -> SpinLockGuard<'_> {
+        let ack = if FORCE_RELAXED { Ordering::Relaxed } else { ack };
+        let rel = if FORCE_RELAXED { Ordering::Relaxed } else { rel };
+
+        let mut step = 0;
+        while self
+            .locked
+            .compare_exchange_weak(false, true, ack, Ordering::Relaxed)
+            .is_err()
+        {
+            for _ in 0..(1u32 << step) {
+                core::hint::spin_loop();
+            }
+            if step < MAX_BACKOFF_STEPS {
+                step += 1;
+            } else {
+                thread::yield_now();
+            }
+        }
+
+        SpinLockGuard { lock: self, rel }
+    }
+
+    fn unlock(&self, rel: Ordering) {
+        self.locked.store(false, rel);
+    }
+
+    // force unlocked between runs
+    fn reset(&self) {
+        self.locked.store(false, Ordering::SeqCst);
+    }
+}
+
+struct SpinLockGuard<'a> {
+    lock: &'a SpinLock,
+    rel: Ordering,
+}
+
+impl Drop for SpinLockGuard<'_> {
+    fn drop(&mut self) {
+        self.lock.unlock(self.rel);
+    }
+}
+
+// pad T onto its own cache line so two padded values can't false-share
+#[cfg_attr(
+    any(target_arch = "x86_64", target_arch = "aarch64", target_arch = "powerpc64"),
+    repr(align(128))
+)]
+#[cfg_attr(
+    any(
+        target_arch = "arm",
+        target_arch = "mips",
+        target_arch = "mips64",
+        target_arch = "sparc",
+        target_arch = "hexagon",
+    ),
+    repr(align(32))
+)]
+#[cfg_attr(
+    not(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "powerpc64",
+        target_arch = "arm",
+        target_arch = "mips",
+        target_arch = "mips64",
+        target_arch = "sparc",
+        target_arch = "hexagon",
+    )),
+    repr(align(64))
+)]
+struct CachePadded<T> {
+    value: T,
+}
+
+impl<T> CachePadded<T> {
+    const fn new(value: T) -> Self {
+        CachePadded { value }
+    }
+}
+
+impl<T> Deref for CachePadded<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T> DerefMut for CachePadded<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+// padded onto separate cache lines vs deliberately packed onto one
+#[derive(Clone, Copy)]
+enum Packing {
+    Padded,
+    Packed,
+}
+
+// blocks each participant in wait() until they've all arrived, so workers
+// are released into the CAS loop at nearly the same instant
+struct Barrier {
+    arrived: AtomicUsize,
+    participants: usize,
+}
+
+impl Barrier {
+    fn new(participants: usize) -> Self {
+        Barrier {
+            arrived: AtomicUsize::new(0),
+            participants,
+        }
+    }
+
+    fn wait(&self) {
+        self.arrived.fetch_add(1, Ordering::SeqCst);
+        while self.arrived.load(Ordering::SeqCst) < self.participants {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+static FLAG: CachePadded<SpinLock> = CachePadded::new(SpinLock::new());
+static mut SHARED_VALUE: CachePadded<u32> = CachePadded::new(0);
+
+// flag and counter packed together with no padding, for the bench comparison
+struct PackedState {
+    flag: SpinLock,
+    value: u32,
+}
+
+static mut PACKED_STATE: PackedState = PackedState {
+    flag: SpinLock::new(),
+    value: 0,
+};
+
+// seqlock mirror of the shared counter; VERSION is odd mid-write
+static VERSION: AtomicU32 = AtomicU32::new(0);
+static MIRROR: AtomicU32 = AtomicU32::new(0);
+
+// tells the observer thread spawned by run_once to stop polling
+static DONE: AtomicBool = AtomicBool::new(false);
+
+fn seqlock_begin_write() {
+    VERSION.fetch_add(1, Ordering::Relaxed);
+}
+
+fn seqlock_end_write(value: u32, rel: Ordering) {
+    MIRROR.store(value, Ordering::Relaxed);
+    if rel != Ordering::Relaxed {
+        fence(rel);
+    }
+    VERSION.fetch_add(1, Ordering::Relaxed);
+}
+
+fn seqlock_read(ack: Ordering) -> Option<u32> {
+    let before = VERSION.load(ack);
+    if !before.is_multiple_of(2) {
+        return None;
+    }
+    let value = MIRROR.load(ack);
+    let after = VERSION.load(ack);
+    if before != after {
+        return None;
+    }
+    Some(value)
+}
+
+// polls MIRROR until DONE, counting backward-moving reads
+fn observe_monotonicity(ack: Ordering) -> u32 {
+    let mut anomalies = 0;
+    let mut last_seen = 0;
+    let mut step = 0;
+    while !DONE.load(Ordering::Relaxed) {
+        if let Some(value) = seqlock_read(ack) {
+            if value < last_seen {
+                anomalies += 1;
+            } else {
+                last_seen = value;
+            }
+        }
+
+        for _ in 0..(1u32 << step) {
+            core::hint::spin_loop();
+        }
+        if step < MAX_BACKOFF_STEPS {
+            step += 1;
+        }
+    }
+    anomalies
+}
+
+/// CAS success orderings worth sweeping for the acquire side.
+const ACK_CANDIDATES: [Ordering; 3] = [Ordering::Relaxed, Ordering::Acquire, Ordering::SeqCst];
+/// Store orderings worth sweeping for the release side.
+const REL_CANDIDATES: [Ordering; 3] = [Ordering::Relaxed, Ordering::Release, Ordering::SeqCst];
+
+fn ordering_name(o: Ordering) -> &'static str {
+    match o {
+        Ordering::Relaxed => "relaxed",
+        Ordering::Acquire => "acquire",
+        Ordering::Release => "release",
+        Ordering::AcqRel => "acqrel",
+        Ordering::SeqCst => "seqcst",
+        _ => "unknown",
+    }
+}
+
+fn parse_ordering(s: &str) -> Ordering {
+    match s.to_ascii_lowercase().as_str() {
+        "relaxed" => Ordering::Relaxed,
+        "acquire" => Ordering::Acquire,
+        "release" => Ordering::Release,
+        "acqrel" => Ordering::AcqRel,
+        "seqcst" => Ordering::SeqCst,
+        other => panic!("unknown ordering {other:?} (expected one of relaxed/acquire/release/acqrel/seqcst)"),
+    }
+}
 
 #[inline(never)]
 fn do_busy_work(v: *mut i32) {
@@ -26,49 +260,176 @@ fn do_busy_work(v: *mut i32) {
 
 const INCREMENTS: usize = 10_000_000;
 #[inline(never)]
-fn increment_shared_value() {
+fn increment_shared_value(ack: Ordering, rel: Ordering, packing: Packing, barrier: &Barrier) {
+    barrier.wait();
+
     let mut count = 0;
     while count < INCREMENTS {
         let mut v = 0;
         do_busy_work(&mut v as _);
 
-        if FLAG
-            .compare_exchange(false, true, ACK, Ordering::Relaxed)
-            .is_ok()
-        {
-            // increment
-            unsafe {
-                SHARED_VALUE += 1;
+        match packing {
+            Packing::Padded => {
+                let _guard = FLAG.lock(ack, rel);
+                // increment
+                let new_value = unsafe {
+                    let shared_value = &mut *std::ptr::addr_of_mut!(SHARED_VALUE);
+                    shared_value.value += 1;
+                    shared_value.value
+                };
+                seqlock_begin_write();
+                seqlock_end_write(new_value, rel);
             }
-            // store
-            FLAG.store(false, REL);
-            // counter
-            count += 1;
+            Packing::Packed => unsafe {
+                let state = &mut *std::ptr::addr_of_mut!(PACKED_STATE);
+                let _guard = state.flag.lock(ack, rel);
+                // increment
+                state.value += 1;
+                let new_value = state.value;
+                seqlock_begin_write();
+                seqlock_end_write(new_value, rel);
+            },
         }
+        // counter
+        count += 1;
     }
 }
 
-pub fn main() {
-    let threads_count = std::env::args()
-        .nth(1)
-        .unwrap_or_else(|| "2".into())
-        .parse()
-        .expect("the first argument to be a thread count");
+struct RunResult {
+    shared_value: u32,
+    anomalies: u32,
+}
+
+// observe spawns a non-blocking reader thread polling the seqlock mirror;
+// callers that only care about throughput (e.g. time_run) should pass false
+// so it doesn't steal a core from the measurement
+fn run_once(threads_count: usize, ack: Ordering, rel: Ordering, packing: Packing, observe: bool) -> RunResult {
+    match packing {
+        Packing::Padded => {
+            unsafe {
+                let shared_value = &mut *std::ptr::addr_of_mut!(SHARED_VALUE);
+                shared_value.value = 0;
+            }
+            FLAG.reset();
+        }
+        Packing::Packed => unsafe {
+            let state = &mut *std::ptr::addr_of_mut!(PACKED_STATE);
+            state.value = 0;
+            state.flag.reset();
+        },
+    }
+    VERSION.store(0, Ordering::Relaxed);
+    MIRROR.store(0, Ordering::Relaxed);
+    DONE.store(false, Ordering::Relaxed);
 
+    let observer = observe.then(|| thread::spawn(move || observe_monotonicity(ack)));
+
+    let barrier = Arc::new(Barrier::new(threads_count));
     let mut threads = Vec::with_capacity(threads_count);
+    for _ in 0..threads_count {
+        let barrier = Arc::clone(&barrier);
+        threads.push(thread::spawn(move || increment_shared_value(ack, rel, packing, &barrier)));
+    }
+    for t in threads {
+        t.join().unwrap();
+    }
 
-    loop {
-        unsafe {
-            SHARED_VALUE = 0;
-        }
+    DONE.store(true, Ordering::Relaxed);
+    let anomalies = observer.map_or(0, |o| o.join().unwrap());
 
-        for _ in 0..threads_count {
-            threads.push(thread::spawn(increment_shared_value));
-        }
+    let shared_value = match packing {
+        Packing::Padded => unsafe { (*std::ptr::addr_of!(SHARED_VALUE)).value },
+        Packing::Packed => unsafe { (*std::ptr::addr_of!(PACKED_STATE)).value },
+    };
+
+    RunResult { shared_value, anomalies }
+}
+
+fn time_run(threads_count: usize, ack: Ordering, rel: Ordering, packing: Packing) -> Duration {
+    let start = Instant::now();
+    run_once(threads_count, ack, rel, packing, false);
+    start.elapsed()
+}
+
+// padded vs packed throughput, to show the false-sharing penalty directly
+fn bench(threads_count: usize) {
+    let ack = Ordering::Acquire;
+    let rel = Ordering::Release;
+
+    let padded = time_run(threads_count, ack, rel, Packing::Padded);
+    let packed = time_run(threads_count, ack, rel, Packing::Packed);
+
+    let total_increments = (INCREMENTS * threads_count) as f64;
+    let padded_throughput = total_increments / padded.as_secs_f64();
+    let packed_throughput = total_increments / packed.as_secs_f64();
 
-        for t in threads.drain(..) {
-            t.join().unwrap();
+    println!("padded: {padded:>10.2?} ({padded_throughput:.0} incr/s)");
+    println!("packed: {packed:>10.2?} ({packed_throughput:.0} incr/s)");
+    println!(
+        "false-sharing penalty: packed is {:.2}x slower than padded",
+        packed.as_secs_f64() / padded.as_secs_f64()
+    );
+}
+
+/// Iterate the full cross-product of CAS-success orderings against
+/// store orderings, reporting which combinations corrupt `SHARED_VALUE`.
+fn sweep(threads_count: usize) {
+    let expected = (INCREMENTS * threads_count) as u32;
+    println!("expected shared value = {expected}");
+    println!(
+        "{:<10} {:<10} {:<12} {:<10} result",
+        "ack", "rel", "shared value", "anomalies"
+    );
+
+    for &ack in &ACK_CANDIDATES {
+        for &rel in &REL_CANDIDATES {
+            let result = run_once(threads_count, ack, rel, Packing::Padded, true);
+            let verdict = if result.shared_value == expected { "correct" } else { "CORRUPTED" };
+            println!(
+                "{:<10} {:<10} {:<12} {:<10} {}",
+                ordering_name(ack),
+                ordering_name(rel),
+                result.shared_value,
+                result.anomalies,
+                verdict
+            );
         }
-        println!("shared value = {}", unsafe { SHARED_VALUE });
+    }
+}
+
+pub fn main() {
+    let mut args = std::env::args().skip(1);
+    let first = args.next().unwrap_or_else(|| "2".into());
+
+    if first == "sweep" {
+        let threads_count = args
+            .next()
+            .unwrap_or_else(|| "2".into())
+            .parse()
+            .expect("the sweep thread count to be a number");
+        sweep(threads_count);
+        return;
+    }
+
+    if first == "bench" {
+        let threads_count = args
+            .next()
+            .unwrap_or_else(|| "2".into())
+            .parse()
+            .expect("the bench thread count to be a number");
+        bench(threads_count);
+        return;
+    }
+
+    let threads_count: usize = first.parse().expect("the first argument to be a thread count");
+    let ack = args.next().map_or(Ordering::Relaxed, |s| parse_ordering(&s));
+    let rel = args.next().map_or(Ordering::Relaxed, |s| parse_ordering(&s));
+
+    loop {
+        let result = run_once(threads_count, ack, rel, Packing::Padded, true);
+        println!(
+            "shared value = {}, anomalies detected = {}",
+            result.shared_value, result.anomalies
+        );
     }
 }